@@ -1,35 +1,30 @@
+use jenna_reactor::{Async, Reactor};
+use std::time::Duration;
+
 fn main() {
-    jenna_reactor::Reactor::run(|reactor| {
-        let mut accepted_connections_count = 0;
+    let reactor = Reactor::new();
 
-        let mut listener = ::mio::net::TcpListener::bind(&"0.0.0.0:7777".parse().unwrap())
-            .expect("failed to bind");
-        let tcp_token = reactor.issue_token();
+    let listener = Async::new(
+        &reactor,
+        ::mio::net::TcpListener::bind(&"0.0.0.0:7777".parse().unwrap()).expect("failed to bind"),
+    )
+    .expect("failed to register listener");
 
-        reactor
-            .poll
-            .register(
-                &listener,
-                tcp_token,
-                ::mio::Ready::readable(),
-                ::mio::PollOpt::level(),
-            )
-            .expect("failed to register");
+    let canceller = reactor.set_interval(Duration::from_millis(1000), || println!("banana"));
+    reactor.set_timeout(Duration::from_millis(9000), move || canceller.cancel());
 
-        reactor.set_event_listener(tcp_token, move |reactor, _| {
-            let connection = listener.accept().expect("failed to accept");
+    reactor.spawn(async move {
+        let mut accepted_connections_count = 0;
+        loop {
+            let (connection, addr) = listener.accept().await.expect("failed to accept");
             accepted_connections_count += 1;
-            println!("{:?}, {:?}", connection, accepted_connections_count);
+            println!("{:?}, {:?}", addr, accepted_connections_count);
+            drop(connection);
             if accepted_connections_count >= 4 {
-                reactor.remove_event_listener(tcp_token);
+                break;
             }
-        });
-
-        let canceller = reactor.set_interval(::std::time::Duration::from_millis(1000), |_| {
-            println!("banana")
-        });
-        reactor.set_timeout(::std::time::Duration::from_millis(9000), move |_| {
-            canceller.cancel();
-        });
+        }
     });
+
+    reactor.run();
 }
@@ -0,0 +1,199 @@
+use crate::reactor::Reactor;
+use mio::{Evented, PollOpt, Ready, Token};
+use std::{
+    cell::RefCell,
+    future::Future,
+    io::{self, Read, Write},
+    net::SocketAddr,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll, Waker},
+};
+
+#[derive(Default)]
+struct AsyncState {
+    readable_ready: bool,
+    readable_waker: Option<Waker>,
+    writable_ready: bool,
+    writable_waker: Option<Waker>,
+}
+
+/// Wraps a `mio::Evented` source, registering it with a `Reactor` and
+/// exposing `async`-friendly readiness notifications instead of raw
+/// `FnMut(Event)` callbacks.
+pub struct Async<T: Evented> {
+    reactor: Reactor,
+    token: Token,
+    source: T,
+    state: Rc<RefCell<AsyncState>>,
+}
+
+impl<T: Evented> Async<T> {
+    pub fn new(reactor: &Reactor, source: T) -> io::Result<Async<T>> {
+        let token = reactor.register(
+            &source,
+            Ready::readable() | Ready::writable(),
+            PollOpt::edge(),
+        );
+
+        let state: Rc<RefCell<AsyncState>> = Default::default();
+        let listener_state = state.clone();
+        reactor.set_event_listener(token, move |event| {
+            let readiness = event.readiness();
+            let mut state = listener_state.borrow_mut();
+            if readiness.is_readable() {
+                state.readable_ready = true;
+                if let Some(waker) = state.readable_waker.take() {
+                    waker.wake();
+                }
+            }
+            if readiness.is_writable() {
+                state.writable_ready = true;
+                if let Some(waker) = state.writable_waker.take() {
+                    waker.wake();
+                }
+            }
+        });
+
+        Ok(Async {
+            reactor: reactor.clone(),
+            token,
+            source,
+            state,
+        })
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.source
+    }
+
+    pub async fn readable(&self) {
+        Readable { async_io: self }.await
+    }
+
+    pub async fn writable(&self) {
+        Writable { async_io: self }.await
+    }
+
+    pub async fn read_with<R>(&self, mut op: impl FnMut(&T) -> io::Result<R>) -> io::Result<R> {
+        loop {
+            match op(&self.source) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.readable().await,
+                result => return result,
+            }
+        }
+    }
+
+    pub async fn write_with<R>(&self, mut op: impl FnMut(&T) -> io::Result<R>) -> io::Result<R> {
+        loop {
+            match op(&self.source) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.writable().await,
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<T: Evented> Drop for Async<T> {
+    fn drop(&mut self) {
+        self.reactor.deregister(self.token, &self.source);
+    }
+}
+
+struct Readable<'a, T: Evented> {
+    async_io: &'a Async<T>,
+}
+
+impl<'a, T: Evented> Future for Readable<'a, T> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let mut state = self.async_io.state.borrow_mut();
+        if state.readable_ready {
+            state.readable_ready = false;
+            Poll::Ready(())
+        } else {
+            state.readable_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+struct Writable<'a, T: Evented> {
+    async_io: &'a Async<T>,
+}
+
+impl<'a, T: Evented> Future for Writable<'a, T> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let mut state = self.async_io.state.borrow_mut();
+        if state.writable_ready {
+            state.writable_ready = false;
+            Poll::Ready(())
+        } else {
+            state.writable_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Async<mio::net::TcpListener> {
+    pub async fn accept(&self) -> io::Result<(Async<mio::net::TcpStream>, SocketAddr)> {
+        let (stream, addr) = self.read_with(|listener| listener.accept()).await?;
+        Ok((Async::new(&self.reactor, stream)?, addr))
+    }
+}
+
+impl Async<mio::net::TcpStream> {
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_with(|stream| (&*stream).read(buf)).await
+    }
+
+    pub async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.write_with(|stream| (&*stream).write(buf)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reactor::Reactor;
+    use std::net::TcpStream as StdTcpStream;
+
+    #[test]
+    fn accept_then_read_and_write_round_trip() {
+        let reactor = Reactor::new();
+        let listener = mio::net::TcpListener::bind(&"127.0.0.1:0".parse().unwrap())
+            .expect("failed to bind");
+        let addr = listener.local_addr().unwrap();
+        let listener = Async::new(&reactor, listener).expect("failed to register listener");
+
+        let client = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            let mut stream = StdTcpStream::connect(addr).expect("failed to connect");
+            stream.write_all(b"ping").expect("failed to write");
+            let mut reply = [0u8; 4];
+            stream.read_exact(&mut reply).expect("failed to read");
+            assert_eq!(&reply, b"pong");
+        });
+
+        reactor.block_on(async move {
+            let (connection, _addr) = listener.accept().await.expect("failed to accept");
+
+            let mut request = [0u8; 4];
+            let mut read = 0;
+            while read < request.len() {
+                read += connection
+                    .read(&mut request[read..])
+                    .await
+                    .expect("failed to read");
+            }
+            assert_eq!(&request, b"ping");
+
+            connection.write(b"pong").await.expect("failed to write");
+        });
+
+        client.join().expect("client thread failed");
+    }
+}
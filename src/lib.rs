@@ -0,0 +1,5 @@
+pub mod async_io;
+pub mod reactor;
+
+pub use async_io::Async;
+pub use reactor::{IntervalHandle, Reactor, ReactorNotifier, ReactorWeak, TimeoutHandle};
@@ -1,11 +1,21 @@
-use mio::{Event, Events, Poll, Token};
+use mio::{Event, Evented, Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
 use std::{
     cell::RefCell,
-    collections::BTreeMap,
+    collections::{BTreeMap, VecDeque},
+    future::Future,
+    pin::Pin,
     rc::Rc,
+    sync::{Arc, Mutex},
+    task::{Context, Poll as TaskPoll, RawWaker, RawWakerVTable, Waker},
     time::{Duration, Instant},
 };
 
+/// Reserved token for the cross-thread notifier self-pipe. `usize::MAX`
+/// itself is off limits: mio reserves it internally for its own readiness
+/// queue and rejects registrations that use it. `issue_token` stops one
+/// short of here, so this can never collide with a caller-issued token.
+const NOTIFY_TOKEN: Token = Token(usize::MAX - 1);
+
 #[derive(Clone)]
 pub struct Reactor {
     i: Rc<RefCell<ReactorInternal>>,
@@ -23,12 +33,36 @@ impl ReactorWeak {
     }
 }
 
+type EventListener = Rc<RefCell<dyn FnMut(Event)>>;
+type NotifyQueue = Arc<Mutex<Vec<Box<dyn FnOnce(&Reactor) + Send>>>>;
+/// Atomically refcounted so a `TaskWaker` built on top of it can be soundly
+/// cloned, woken, and dropped from any thread, as `std::task::Waker`'s
+/// `Send + Sync` contract requires.
+type ReadyQueue = Arc<Mutex<VecDeque<usize>>>;
+
 struct ReactorInternal {
     poll: Poll,
     quit: bool,
     token_ticker: usize,
-    event_listeners: BTreeMap<Token, Rc<RefCell<FnMut(Event)>>>,
-    timeout_listeners: Vec<(Instant, Box<FnOnce()>)>,
+    event_listeners: BTreeMap<Token, EventListener>,
+    timeout_listeners: BTreeMap<(Instant, usize), Box<dyn FnOnce()>>,
+    timer_ticker: usize,
+    tasks: BTreeMap<usize, Task>,
+    task_ticker: usize,
+    ready_queue: ReadyQueue,
+    _notify_registration: Registration,
+    notify_set_readiness: SetReadiness,
+    notify_queue: NotifyQueue,
+}
+
+struct Task {
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Default for Reactor {
+    fn default() -> Self {
+        Reactor::new()
+    }
 }
 
 impl Reactor {
@@ -60,6 +94,19 @@ impl Reactor {
         let token_ticker = 0;
         let event_listeners = Default::default();
         let timeout_listeners = Default::default();
+        let timer_ticker = 0;
+        let tasks = Default::default();
+        let task_ticker = 0;
+        let ready_queue = Default::default();
+        let (notify_registration, notify_set_readiness) = Registration::new2();
+        poll.register(
+            &notify_registration,
+            NOTIFY_TOKEN,
+            Ready::readable(),
+            PollOpt::edge(),
+        )
+        .expect("failed to register notifier");
+        let notify_queue = Default::default();
         Reactor {
             i: Rc::new(RefCell::new(ReactorInternal {
                 poll,
@@ -67,10 +114,39 @@ impl Reactor {
                 token_ticker,
                 event_listeners,
                 timeout_listeners,
+                timer_ticker,
+                tasks,
+                task_ticker,
+                ready_queue,
+                _notify_registration: notify_registration,
+                notify_set_readiness,
+                notify_queue,
             })),
         }
     }
 
+    /// Returns a `Send + Sync` handle that other threads can use to
+    /// schedule a closure to run on the reactor's own thread, interrupting
+    /// a blocking `poll` the way a self-pipe interrupts `select`.
+    pub fn notifier(&self) -> ReactorNotifier {
+        let lock = self.i.borrow();
+        ReactorNotifier {
+            set_readiness: lock.notify_set_readiness.clone(),
+            queue: lock.notify_queue.clone(),
+        }
+    }
+
+    fn drain_notify_queue(&self) {
+        let queue = self.i.borrow().notify_queue.clone();
+        let closures = std::mem::take(&mut *queue.lock().unwrap());
+        for closure in closures {
+            closure(self);
+            if self.i.borrow().quit {
+                break;
+            }
+        }
+    }
+
     pub fn quit(&self) {
         self.i.borrow_mut().quit = true;
     }
@@ -86,12 +162,31 @@ impl Reactor {
         self.i.borrow_mut().event_listeners.remove(&token);
     }
 
-    pub fn set_timeout(&self, timeout: Duration, callback: impl FnOnce() + 'static) {
-        let run_at = Instant::now() + timeout;
+    pub fn set_timeout(
+        &self,
+        timeout: Duration,
+        callback: impl FnOnce() + 'static,
+    ) -> TimeoutHandle {
+        let deadline = Instant::now() + timeout;
+        let id = self.issue_timer_id();
         self.i
             .borrow_mut()
             .timeout_listeners
-            .push((run_at, Box::new(callback)));
+            .insert((deadline, id), Box::new(callback));
+        TimeoutHandle {
+            reactor: self.clone(),
+            deadline,
+            id,
+        }
+    }
+
+    fn issue_timer_id(&self) -> usize {
+        let mine = self.i.borrow().timer_ticker;
+        if mine == usize::MAX {
+            panic!("Out of timer ids");
+        }
+        self.i.borrow_mut().timer_ticker += 1;
+        mine
     }
 
     pub fn set_interval(
@@ -100,7 +195,7 @@ impl Reactor {
         callback: impl FnMut() + 'static,
     ) -> IntervalHandle {
         let handle = IntervalHandle {
-            cancelled: Rc::new(RefCell::new(false)),
+            current: Rc::new(RefCell::new(None)),
         };
         let callback = Rc::new(RefCell::new(callback));
         self.reschedule_interval(interval, handle.clone(), callback);
@@ -114,100 +209,419 @@ impl Reactor {
         callback: Rc<RefCell<dyn FnMut()>>,
     ) {
         let proxy = self.clone();
-        self.set_timeout(interval, move || {
-            if !handle.is_cancelled() {
-                proxy.reschedule_interval(interval, handle, callback.clone());
-                (&mut *callback.borrow_mut())();
-            }
+        let rescheduled_handle = handle.clone();
+        let timeout_handle = self.set_timeout(interval, move || {
+            proxy.reschedule_interval(interval, rescheduled_handle.clone(), callback.clone());
+            (*callback.borrow_mut())();
         });
+        *handle.current.borrow_mut() = Some(timeout_handle);
+    }
+
+    pub fn spawn(&self, fut: impl Future<Output = ()> + 'static) {
+        let id = self.issue_task_id();
+        let task = Task {
+            future: Box::pin(fut),
+        };
+        let mut lock = self.i.borrow_mut();
+        lock.tasks.insert(id, task);
+        lock.ready_queue.lock().unwrap().push_back(id);
+    }
+
+    pub fn block_on<F: Future + 'static>(&self, fut: F) -> F::Output
+    where
+        F::Output: 'static,
+    {
+        let output: Rc<RefCell<Option<F::Output>>> = Rc::new(RefCell::new(None));
+        let done = Rc::new(RefCell::new(false));
+        let output_slot = output.clone();
+        let done_flag = done.clone();
+        self.spawn(async move {
+            let result = fut.await;
+            *output_slot.borrow_mut() = Some(result);
+            *done_flag.borrow_mut() = true;
+        });
+        while !*done.borrow() {
+            self.step();
+        }
+        let result = output
+            .borrow_mut()
+            .take()
+            .expect("task driving block_on finished without producing output");
+        result
+    }
+
+    fn issue_task_id(&self) -> usize {
+        let mine = self.i.borrow().task_ticker;
+        if mine == usize::MAX {
+            panic!("Out of task ids");
+        }
+        self.i.borrow_mut().task_ticker += 1;
+        mine
+    }
+
+    /// Polls every task currently on the ready queue, returning `true` if
+    /// at least one was polled. A task resolving here may be exactly what
+    /// `block_on`/`run` is waiting on, so callers must not block in
+    /// `poll.poll` afterwards without re-checking their stop condition.
+    fn poll_ready_tasks(&self) -> bool {
+        let mut did_work = false;
+        loop {
+            let ready_queue = self.i.borrow().ready_queue.clone();
+            let id = match ready_queue.lock().unwrap().pop_front() {
+                Some(id) => id,
+                None => break,
+            };
+            let task = self.i.borrow_mut().tasks.remove(&id);
+            let mut task = match task {
+                Some(task) => task,
+                None => continue,
+            };
+            did_work = true;
+            let notify_set_readiness = self.i.borrow().notify_set_readiness.clone();
+            let waker = make_waker(id, ready_queue.clone(), notify_set_readiness);
+            let mut cx = Context::from_waker(&waker);
+            match task.future.as_mut().poll(&mut cx) {
+                TaskPoll::Ready(()) => {}
+                TaskPoll::Pending => {
+                    self.i.borrow_mut().tasks.insert(id, task);
+                }
+            }
+        }
+        did_work
     }
 
     pub fn issue_token(&self) -> Token {
         let mine = self.i.borrow().token_ticker;
-        if mine == std::usize::MAX {
+        if mine >= NOTIFY_TOKEN.0 {
             panic!("Out of tokens");
         }
         self.i.borrow_mut().token_ticker += 1;
         Token(mine)
     }
 
+    /// Allocates a token and registers `source` for `interest` under it,
+    /// rolling `issue_token` and `poll(|p| p.register(...))` into a single
+    /// step so a token can't be issued without a matching registration.
+    pub fn register(&self, source: &impl Evented, interest: Ready, opt: PollOpt) -> Token {
+        let token = self.issue_token();
+        self.i
+            .borrow()
+            .poll
+            .register(source, token, interest, opt)
+            .expect("failed to register source");
+        token
+    }
+
+    pub fn reregister(&self, source: &impl Evented, token: Token, interest: Ready, opt: PollOpt) {
+        self.i
+            .borrow()
+            .poll
+            .reregister(source, token, interest, opt)
+            .expect("failed to reregister source");
+    }
+
+    /// Deregisters `source` from the poll and drops any event listener
+    /// still set for `token`, so a caller can't leave a dangling listener
+    /// behind the way a manual `remove_event_listener` call could.
+    pub fn deregister(&self, token: Token, source: &impl Evented) {
+        let _ = self.i.borrow().poll.deregister(source);
+        self.i.borrow_mut().event_listeners.remove(&token);
+    }
+
     fn calculate_duration(&self) -> CalculateDurationResult {
         let now = Instant::now();
-        let mut duration = None;
-        let mut fire_at = None;
-        let mut idx = 0;
-        for (candidate_idx, timeout) in self.i.borrow().timeout_listeners.iter().enumerate() {
-            let candidate = if timeout.0 > now {
-                timeout.0.duration_since(now)
-            } else {
-                Duration::from_millis(0)
-            };
-            if duration.is_none() || candidate < duration.unwrap() {
-                duration = Some(candidate);
-                fire_at = Some(timeout.0);
-                idx = candidate_idx;
+        match self.i.borrow().timeout_listeners.keys().next() {
+            Some(&(deadline, _)) => {
+                let duration = if deadline > now {
+                    deadline.duration_since(now)
+                } else {
+                    Duration::from_millis(0)
+                };
+                CalculateDurationResult {
+                    duration: Some(duration),
+                    fire_at: Some(deadline),
+                }
             }
-        }
-        CalculateDurationResult {
-            duration,
-            fire_at,
-            idx,
+            None => CalculateDurationResult {
+                duration: None,
+                fire_at: None,
+            },
         }
     }
 
     fn is_empty(&self) -> bool {
-        self.i.borrow().timeout_listeners.is_empty() && self.i.borrow().event_listeners.is_empty()
+        self.i.borrow().timeout_listeners.is_empty()
+            && self.i.borrow().event_listeners.is_empty()
+            && self.i.borrow().tasks.is_empty()
     }
 
     fn run_internal(&self) {
-        let mut events = Events::with_capacity(1024);
         while !self.i.borrow().quit && !self.is_empty() {
-            let duration = self.calculate_duration();
-            self.i
-                .borrow()
-                .poll
-                .poll(&mut events, duration.duration)
-                .expect("poll failed");
-            if let Some(fire_at) = duration.fire_at {
-                if Instant::now() >= fire_at {
-                    let (_, callback) = self.i.borrow_mut().timeout_listeners.remove(duration.idx);
-                    callback();
-                    if self.i.borrow().quit {
-                        break;
+            self.step();
+        }
+    }
+
+    fn step(&self) {
+        let mut events = Events::with_capacity(1024);
+        let did_work = self.poll_ready_tasks();
+        let duration = self.calculate_duration();
+        // A task may have just resolved whatever `block_on`/`run` is
+        // waiting on; don't commit to an indefinite (or even long) block
+        // in `poll` without giving the caller a chance to notice first.
+        let poll_duration = if did_work {
+            Some(Duration::from_millis(0))
+        } else {
+            duration.duration
+        };
+        self.i
+            .borrow()
+            .poll
+            .poll(&mut events, poll_duration)
+            .expect("poll failed");
+        if let Some(fire_at) = duration.fire_at {
+            let now = Instant::now();
+            if now >= fire_at {
+                let due: Vec<(Instant, usize)> = self
+                    .i
+                    .borrow()
+                    .timeout_listeners
+                    .range(..=(now, usize::MAX))
+                    .map(|(&key, _)| key)
+                    .collect();
+                for key in due {
+                    let callback = self.i.borrow_mut().timeout_listeners.remove(&key);
+                    if let Some(callback) = callback {
+                        callback();
+                        if self.i.borrow().quit {
+                            return;
+                        }
                     }
                 }
             }
-            for event in events.iter() {
-                let token = event.token();
-                let handler = self.i.borrow().event_listeners.get(&token).cloned();
-                if let Some(handler) = handler {
-                    (&mut *handler.borrow_mut())(event);
-                    if self.i.borrow().quit {
-                        break;
-                    }
+        }
+        for event in events.iter() {
+            let token = event.token();
+            if token == NOTIFY_TOKEN {
+                self.drain_notify_queue();
+                if self.i.borrow().quit {
+                    break;
+                }
+                continue;
+            }
+            let handler = self.i.borrow().event_listeners.get(&token).cloned();
+            if let Some(handler) = handler {
+                (*handler.borrow_mut())(event);
+                if self.i.borrow().quit {
+                    break;
                 }
             }
         }
     }
 }
 
+/// A `Send + Sync` handle for scheduling work on a `Reactor` from another
+/// thread. Posting a closure wakes a blocked `poll` via a self-pipe and
+/// runs the closure on the reactor's own thread once drained.
+#[derive(Clone)]
+pub struct ReactorNotifier {
+    set_readiness: SetReadiness,
+    queue: NotifyQueue,
+}
+
+impl ReactorNotifier {
+    pub fn post(&self, closure: impl FnOnce(&Reactor) + Send + 'static) {
+        self.queue.lock().unwrap().push(Box::new(closure));
+        let _ = self.set_readiness.set_readiness(Ready::readable());
+    }
+}
+
+/// Backs the `RawWaker` handed out for each task. `std::task::Waker` is
+/// unconditionally `Send + Sync`, so nothing stops a future from moving
+/// `cx.waker().clone()` to another thread (e.g. alongside a
+/// `ReactorNotifier`) and calling `wake()` there; both the refcount
+/// (`Arc`, not `Rc`) and the queue (`ReadyQueue` = `Arc<Mutex<..>>`) must
+/// therefore be safe to touch concurrently. Waking from another thread
+/// also has to interrupt a blocking `poll.poll(...)` on the reactor's own
+/// thread, so `wake`/`wake_by_ref` set readiness on the same self-pipe
+/// `ReactorNotifier::post` uses, not just push onto `ready_queue`.
+struct TaskWaker {
+    id: usize,
+    ready_queue: ReadyQueue,
+    notify_set_readiness: SetReadiness,
+}
+
+fn into_raw_waker(data: Arc<TaskWaker>) -> RawWaker {
+    RawWaker::new(Arc::into_raw(data) as *const (), &TASK_WAKER_VTABLE)
+}
+
+unsafe fn task_waker_clone(ptr: *const ()) -> RawWaker {
+    let original = Arc::from_raw(ptr as *const TaskWaker);
+    let cloned = original.clone();
+    std::mem::forget(original);
+    into_raw_waker(cloned)
+}
+
+unsafe fn task_waker_wake(ptr: *const ()) {
+    let waker = Arc::from_raw(ptr as *const TaskWaker);
+    waker.ready_queue.lock().unwrap().push_back(waker.id);
+    let _ = waker.notify_set_readiness.set_readiness(Ready::readable());
+}
+
+unsafe fn task_waker_wake_by_ref(ptr: *const ()) {
+    let waker = Arc::from_raw(ptr as *const TaskWaker);
+    waker.ready_queue.lock().unwrap().push_back(waker.id);
+    let _ = waker.notify_set_readiness.set_readiness(Ready::readable());
+    std::mem::forget(waker);
+}
+
+unsafe fn task_waker_drop(ptr: *const ()) {
+    drop(Arc::from_raw(ptr as *const TaskWaker));
+}
+
+static TASK_WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(
+    task_waker_clone,
+    task_waker_wake,
+    task_waker_wake_by_ref,
+    task_waker_drop,
+);
+
+fn make_waker(id: usize, ready_queue: ReadyQueue, notify_set_readiness: SetReadiness) -> Waker {
+    let data = Arc::new(TaskWaker {
+        id,
+        ready_queue,
+        notify_set_readiness,
+    });
+    unsafe { Waker::from_raw(into_raw_waker(data)) }
+}
+
 struct CalculateDurationResult {
     duration: Option<Duration>,
     fire_at: Option<Instant>,
-    idx: usize,
+}
+
+pub struct TimeoutHandle {
+    reactor: Reactor,
+    deadline: Instant,
+    id: usize,
+}
+
+impl TimeoutHandle {
+    pub fn cancel(&self) {
+        self.reactor
+            .i
+            .borrow_mut()
+            .timeout_listeners
+            .remove(&(self.deadline, self.id));
+    }
 }
 
 #[derive(Clone)]
 pub struct IntervalHandle {
-    cancelled: Rc<RefCell<bool>>,
+    current: Rc<RefCell<Option<TimeoutHandle>>>,
 }
 
 impl IntervalHandle {
     pub fn cancel(&self) {
-        *self.cancelled.borrow_mut() = true;
+        if let Some(handle) = self.current.borrow_mut().take() {
+            handle.cancel();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_on_resolves_an_already_ready_future() {
+        let reactor = Reactor::new();
+        let value = reactor.block_on(async { 1 + 2 });
+        assert_eq!(value, 3);
+    }
+
+    #[test]
+    fn waker_cloned_to_another_thread_wakes_a_blocked_reactor() {
+        use std::future::Future;
+        use std::pin::Pin;
+        use std::task::{Context, Poll};
+
+        struct WakeFromThread {
+            waker_sent: bool,
+        }
+
+        impl Future for WakeFromThread {
+            type Output = ();
+
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+                if self.waker_sent {
+                    return Poll::Ready(());
+                }
+                self.waker_sent = true;
+                let waker = cx.waker().clone();
+                std::thread::spawn(move || {
+                    std::thread::sleep(Duration::from_millis(300));
+                    waker.wake();
+                });
+                Poll::Pending
+            }
+        }
+
+        let reactor = Reactor::new();
+        reactor.block_on(WakeFromThread { waker_sent: false });
+    }
+
+    #[test]
+    fn cancelled_timeout_does_not_fire() {
+        let reactor = Reactor::new();
+        let fired = Rc::new(RefCell::new(false));
+        let fired_flag = fired.clone();
+        let handle = reactor.set_timeout(Duration::from_millis(50), move || {
+            *fired_flag.borrow_mut() = true;
+        });
+        handle.cancel();
+
+        let quitter = reactor.clone();
+        reactor.set_timeout(Duration::from_millis(100), move || quitter.quit());
+        reactor.run();
+
+        assert!(!*fired.borrow());
     }
 
-    fn is_cancelled(&self) -> bool {
-        *self.cancelled.borrow()
+    #[test]
+    fn notifier_runs_closure_on_reactor_thread_from_another_thread() {
+        let reactor = Reactor::new();
+        let notifier = reactor.notifier();
+        let ran = Arc::new(Mutex::new(false));
+        let ran_flag = ran.clone();
+
+        let quitter = reactor.clone();
+        reactor.set_timeout(Duration::from_millis(2000), move || quitter.quit());
+
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            notifier.post(move |reactor| {
+                *ran_flag.lock().unwrap() = true;
+                reactor.quit();
+            });
+        });
+
+        reactor.run();
+        assert!(*ran.lock().unwrap());
+    }
+
+    #[test]
+    fn register_reregister_deregister_round_trip() {
+        let reactor = Reactor::new();
+        let listener =
+            mio::net::TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).expect("failed to bind");
+
+        let token = reactor.register(&listener, Ready::readable(), PollOpt::edge());
+        reactor.reregister(
+            &listener,
+            token,
+            Ready::readable() | Ready::writable(),
+            PollOpt::edge(),
+        );
+        reactor.deregister(token, &listener);
     }
 }